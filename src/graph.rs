@@ -1,13 +1,111 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use raug::{graph::Graph, prelude::*};
-use raug_ext::prelude::{BlSawOscillator, SineOscillator};
+use raug::prelude::*;
 use raug_graph::{builder::IntoIndex, graph::NodeIndex};
-use rosc::{OscMessage, OscPacket, OscType};
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use std::collections::HashMap;
 use thiserror::Error;
 use tokio::net::{ToSocketAddrs, UdpSocket};
 
 use crate::server::Server;
 
+/// OSC address of the sequence-id message that prefixes each reliably-sent op bundle.
+pub const SEQ_ADDR: &str = "/seq";
+/// OSC address of the acknowledgement the server returns once a sequence id is applied.
+pub const ACK_ADDR: &str = "/ack";
+
+/// Immediate OSC timetag (seconds = 0, fractional = 1), used for control bundles.
+const IMMEDIATE: OscTime = OscTime {
+    seconds: 0,
+    fractional: 1,
+};
+
+/// Wraps `packet` in a bundle prefixed with a `/seq <seq>` message so the server
+/// can acknowledge and deduplicate it.
+pub fn seq_bundle(seq: u32, packet: OscPacket) -> OscPacket {
+    OscPacket::Bundle(OscBundle {
+        timetag: IMMEDIATE,
+        content: vec![
+            OscPacket::Message(OscMessage {
+                addr: SEQ_ADDR.to_string(),
+                args: vec![OscType::Int(seq as i32)],
+            }),
+            packet,
+        ],
+    })
+}
+
+/// Splits a possibly sequence-tagged packet into its sequence id (if any) and the
+/// wrapped op packet. Packets without a leading `/seq` message are returned as-is.
+pub fn take_seq(packet: OscPacket) -> (Option<u32>, OscPacket) {
+    if let OscPacket::Bundle(mut bund) = packet {
+        if bund.content.len() >= 2 {
+            if let Some(OscPacket::Message(msg)) = bund.content.first() {
+                if msg.addr == SEQ_ADDR {
+                    if let Some(OscType::Int(seq)) = msg.args.first().cloned() {
+                        let inner = bund.content.swap_remove(1);
+                        return (Some(seq as u32), inner);
+                    }
+                }
+            }
+        }
+        (None, OscPacket::Bundle(bund))
+    } else {
+        (None, packet)
+    }
+}
+
+/// Builds an `/ack <seq>` message.
+pub fn ack(seq: u32) -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: ACK_ADDR.to_string(),
+        args: vec![OscType::Int(seq as i32)],
+    })
+}
+
+/// Returns the acknowledged sequence id if `packet` is an `/ack` message.
+pub fn parse_ack(packet: &OscPacket) -> Option<u32> {
+    if let OscPacket::Message(msg) = packet {
+        if msg.addr == ACK_ADDR {
+            if let Some(OscType::Int(seq)) = msg.args.first() {
+                return Some(*seq as u32);
+            }
+        }
+    }
+    None
+}
+
+/// Initial retransmission timeout, doubled on each attempt up to [`MAX_RETRY_TIMEOUT`].
+const INITIAL_RETRY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Ceiling for the exponentially-backed-off retransmission timeout.
+const MAX_RETRY_TIMEOUT: Duration = Duration::from_secs(32);
+/// Overall deadline after which a reliable request gives up.
+const RELIABLE_DEADLINE: Duration = Duration::from_secs(120);
+
+/// An op awaiting acknowledgement, carrying its encoded packet and retry state.
+struct Unacked {
+    packet: Vec<u8>,
+    tries: u32,
+    timeout: Duration,
+    next_retry: Instant,
+}
+
+#[derive(Error, Debug)]
+#[error("reliable request for seq {seq} timed out after {tries} attempts")]
+pub struct ReliableTimeoutError {
+    seq: u32,
+    tries: u32,
+}
+
+/// Monotonically increasing sequence id shared by every reliable request.
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(1);
+
+fn next_seq() -> u32 {
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Error, Debug)]
 #[error("Invalid name or index")]
 pub struct InvalidNameOrIndexError;
@@ -69,6 +167,14 @@ pub struct InvalidGraphOpResponseError(String);
 #[derive(Debug, Clone, PartialEq)]
 pub enum GraphOpResponse {
     NodeIndex(NodeIndex),
+    String(String),
+    NodeList(Vec<(NodeIndex, String)>),
+    StringList(Vec<String>),
+    NodeInfo {
+        node: NodeIndex,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    },
     None,
 }
 
@@ -86,6 +192,39 @@ impl GraphOpResponse {
                 addr: "/response/node_index".to_string(),
                 args: vec![OscType::Int(i.index() as i32)],
             }),
+            GraphOpResponse::String(s) => OscPacket::Message(OscMessage {
+                addr: "/response/string".to_string(),
+                args: vec![OscType::String(s)],
+            }),
+            GraphOpResponse::NodeList(nodes) => OscPacket::Message(OscMessage {
+                addr: "/response/node_list".to_string(),
+                args: nodes
+                    .into_iter()
+                    .flat_map(|(idx, name)| {
+                        [OscType::Int(idx.index() as i32), OscType::String(name)]
+                    })
+                    .collect(),
+            }),
+            GraphOpResponse::StringList(items) => OscPacket::Message(OscMessage {
+                addr: "/response/string_list".to_string(),
+                args: items.into_iter().map(OscType::String).collect(),
+            }),
+            GraphOpResponse::NodeInfo {
+                node,
+                inputs,
+                outputs,
+            } => {
+                let mut args = vec![
+                    OscType::Int(node.index() as i32),
+                    OscType::Int(inputs.len() as i32),
+                ];
+                args.extend(inputs.into_iter().map(OscType::String));
+                args.extend(outputs.into_iter().map(OscType::String));
+                OscPacket::Message(OscMessage {
+                    addr: "/response/node_info".to_string(),
+                    args,
+                })
+            }
             GraphOpResponse::None => OscPacket::Message(OscMessage {
                 addr: "/response/none".to_string(),
                 args: vec![],
@@ -103,6 +242,46 @@ impl GraphOpResponse {
                     let index = index.clone().int().unwrap() as usize;
                     Ok(vec![GraphOpResponse::NodeIndex(NodeIndex::new(index))])
                 }
+                "/response/string" => {
+                    let [s] = &msg.args[..] else { unreachable!() };
+                    let s = s.clone().string().unwrap();
+                    Ok(vec![GraphOpResponse::String(s)])
+                }
+                "/response/node_list" => {
+                    let mut nodes = vec![];
+                    let mut args = msg.args.iter().cloned();
+                    while let (Some(idx), Some(name)) = (args.next(), args.next()) {
+                        nodes.push((
+                            NodeIndex::new(idx.int().unwrap() as usize),
+                            name.string().unwrap(),
+                        ));
+                    }
+                    Ok(vec![GraphOpResponse::NodeList(nodes)])
+                }
+                "/response/string_list" => {
+                    let items = msg
+                        .args
+                        .iter()
+                        .cloned()
+                        .map(|a| a.string().unwrap())
+                        .collect();
+                    Ok(vec![GraphOpResponse::StringList(items)])
+                }
+                "/response/node_info" => {
+                    let node = NodeIndex::new(msg.args[0].clone().int().unwrap() as usize);
+                    let n_inputs = msg.args[1].clone().int().unwrap() as usize;
+                    let names: Vec<String> = msg.args[2..]
+                        .iter()
+                        .cloned()
+                        .map(|a| a.string().unwrap())
+                        .collect();
+                    let (inputs, outputs) = names.split_at(n_inputs);
+                    Ok(vec![GraphOpResponse::NodeInfo {
+                        node,
+                        inputs: inputs.to_vec(),
+                        outputs: outputs.to_vec(),
+                    }])
+                }
                 "/response/none" => Ok(vec![GraphOpResponse::None]),
                 msg => Err(InvalidGraphOpResponseError(msg.to_string()).into()),
             },
@@ -121,6 +300,15 @@ impl GraphOpResponse {
 #[error("Invalid graph op: {0}")]
 pub struct InvalidGraphOpError(String);
 
+/// A single entry in the replayable session log: an applied op together with the
+/// node it created (if any), recorded so a load can remap stored indices onto the
+/// ones produced during replay.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub op: GraphOp,
+    pub created: Option<NodeIndex>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[non_exhaustive]
 pub enum GraphOp {
@@ -136,6 +324,7 @@ pub enum GraphOp {
     },
     AddProcessor {
         name: String,
+        args: Vec<OscType>,
     },
     Connect {
         source: NodeIndex,
@@ -147,20 +336,79 @@ pub enum GraphOp {
         replaced: NodeIndex,
         replacement: NodeIndex,
     },
+    DumpGraph,
+    ListProcessors,
+    ListNodes,
+    NodeInfo {
+        node: NodeIndex,
+    },
+    Save {
+        path: String,
+    },
+    Load {
+        path: String,
+    },
 }
 
 impl GraphOp {
+    /// Sends this op reliably and resolves once both its `/ack` and response have
+    /// arrived. The op is tagged with a sequence id and retransmitted with
+    /// exponential backoff ([`INITIAL_RETRY_TIMEOUT`] doubling to
+    /// [`MAX_RETRY_TIMEOUT`]) until both have arrived, erroring with
+    /// [`ReliableTimeoutError`] after [`RELIABLE_DEADLINE`].
     pub async fn request(
         self,
         sock: &UdpSocket,
-        addr: impl ToSocketAddrs,
+        addr: impl ToSocketAddrs + Clone,
     ) -> Result<GraphOpResponse> {
-        let buf = rosc::encoder::encode(&self.to_osc())?;
-        sock.send_to(&buf, addr).await?;
+        let seq = next_seq();
+        let mut entry = Unacked {
+            packet: rosc::encoder::encode(&seq_bundle(seq, self.to_osc()))?,
+            tries: 0,
+            timeout: INITIAL_RETRY_TIMEOUT,
+            next_retry: Instant::now(),
+        };
+        let deadline = Instant::now() + RELIABLE_DEADLINE;
+
+        let mut acked = false;
+        let mut response: Option<GraphOpResponse> = None;
         let mut buf = [0u8; rosc::decoder::MTU];
-        let (size, _addr) = sock.recv_from(&mut buf).await?;
-        let (_, packet) = rosc::decoder::decode_udp(&buf[..size])?;
-        Ok(GraphOpResponse::try_from_osc(&packet)?.remove(0))
+
+        loop {
+            if Instant::now() >= deadline {
+                return Err(ReliableTimeoutError {
+                    seq,
+                    tries: entry.tries,
+                }
+                .into());
+            }
+
+            if Instant::now() >= entry.next_retry {
+                sock.send_to(&entry.packet, addr.clone()).await?;
+                entry.tries += 1;
+                entry.next_retry = Instant::now() + entry.timeout;
+                entry.timeout = (entry.timeout * 2).min(MAX_RETRY_TIMEOUT);
+            }
+
+            let wait = entry.next_retry.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(wait, sock.recv_from(&mut buf)).await {
+                Ok(Ok((size, _addr))) => {
+                    let (_, packet) = rosc::decoder::decode_udp(&buf[..size])?;
+                    if let Some(ack_seq) = parse_ack(&packet) {
+                        acked |= ack_seq == seq;
+                    } else {
+                        response = Some(GraphOpResponse::try_from_osc(&packet)?.remove(0));
+                    }
+                    if acked {
+                        if let Some(response) = response.take() {
+                            return Ok(response);
+                        }
+                    }
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
     }
 
     pub fn apply(&self, server: &mut Server) -> Result<GraphOpResponse> {
@@ -179,12 +427,12 @@ impl GraphOp {
                 source,
                 source_output,
             } => {
+                let source_output = server
+                    .resolve_output(*source, source_output)
+                    .ok_or(InvalidNameOrIndexError)?;
                 let channel = server.mixer_channel(*mixer_channel).node();
-                let NameOrIndex::Index(source_output) = source_output else {
-                    todo!()
-                };
                 graph.with_inner(|graph| {
-                    graph.connect(*source, *source_output, channel.id(), 0);
+                    graph.connect(*source, source_output, channel.id(), 0);
                 });
                 Ok(GraphOpResponse::None)
             }
@@ -200,8 +448,8 @@ impl GraphOp {
                 let node = graph.node(Constant::new(Str::from(c.as_str())));
                 Ok(GraphOpResponse::NodeIndex(node.id()))
             }
-            GraphOp::AddProcessor { name } => {
-                let node = add_proc_by_name(&graph, name)?;
+            GraphOp::AddProcessor { name, args } => {
+                let node = server.add_processor(name, args)?;
                 Ok(GraphOpResponse::NodeIndex(node))
             }
             GraphOp::Connect {
@@ -221,6 +469,79 @@ impl GraphOp {
                     .with_inner(|graph| graph.replace_node_gracefully(*replaced, *replacement));
                 Ok(GraphOpResponse::NodeIndex(node))
             }
+            GraphOp::DumpGraph => Ok(GraphOpResponse::String(server.dump_dot())),
+            GraphOp::ListProcessors => {
+                Ok(GraphOpResponse::StringList(server.processor_names()))
+            }
+            GraphOp::ListNodes => Ok(GraphOpResponse::NodeList(server.list_nodes())),
+            GraphOp::NodeInfo { node } => {
+                let (inputs, outputs) = server.node_info(*node).unwrap_or_default();
+                Ok(GraphOpResponse::NodeInfo {
+                    node: *node,
+                    inputs,
+                    outputs,
+                })
+            }
+            GraphOp::Save { path } => {
+                server.save(path)?;
+                Ok(GraphOpResponse::None)
+            }
+            GraphOp::Load { path } => {
+                server.load(path)?;
+                Ok(GraphOpResponse::None)
+            }
+        }
+    }
+
+    /// Whether this op mutates graph structure and so belongs in the replayable
+    /// session log. Control, query, and persistence ops are not recorded.
+    pub fn is_recordable(&self) -> bool {
+        matches!(
+            self,
+            GraphOp::AddConstantF32(_)
+                | GraphOp::AddConstantBool(_)
+                | GraphOp::AddConstantString(_)
+                | GraphOp::AddToMix { .. }
+                | GraphOp::AddProcessor { .. }
+                | GraphOp::Connect { .. }
+                | GraphOp::ReplaceNode { .. }
+        )
+    }
+
+    /// Returns a copy of this op with every node reference translated through
+    /// `remap`, used when replaying a loaded log whose indices may differ from
+    /// the ones produced during replay.
+    pub fn remapped(&self, remap: &HashMap<NodeIndex, NodeIndex>) -> GraphOp {
+        let map = |idx: NodeIndex| remap.get(&idx).copied().unwrap_or(idx);
+        match self.clone() {
+            GraphOp::AddToMix {
+                mixer_channel,
+                source,
+                source_output,
+            } => GraphOp::AddToMix {
+                mixer_channel,
+                source: map(source),
+                source_output,
+            },
+            GraphOp::Connect {
+                source,
+                source_output,
+                target,
+                target_input,
+            } => GraphOp::Connect {
+                source: map(source),
+                source_output,
+                target: map(target),
+                target_input,
+            },
+            GraphOp::ReplaceNode {
+                replaced,
+                replacement,
+            } => GraphOp::ReplaceNode {
+                replaced: map(replaced),
+                replacement: map(replacement),
+            },
+            other => other,
         }
     }
 
@@ -258,11 +579,14 @@ impl GraphOp {
                     Ok(vec![GraphOp::AddConstantString(c)])
                 }
                 "/add_processor" => {
-                    let [name] = &msg.args[..] else {
+                    let [name, args @ ..] = &msg.args[..] else {
                         unreachable!()
                     };
                     let name = name.clone().string().unwrap();
-                    Ok(vec![GraphOp::AddProcessor { name }])
+                    Ok(vec![GraphOp::AddProcessor {
+                        name,
+                        args: args.to_vec(),
+                    }])
                 }
                 "/connect" => {
                     let [source, source_output, target, target_input] = &msg.args[..] else {
@@ -294,6 +618,30 @@ impl GraphOp {
                         replacement,
                     }])
                 }
+                "/dump_graph" => Ok(vec![GraphOp::DumpGraph]),
+                "/query/processors" => Ok(vec![GraphOp::ListProcessors]),
+                "/query/nodes" => Ok(vec![GraphOp::ListNodes]),
+                "/query/node_info" => {
+                    let [node] = &msg.args[..] else {
+                        unreachable!()
+                    };
+                    let node = NodeIndex::new(node.clone().int().unwrap() as usize);
+                    Ok(vec![GraphOp::NodeInfo { node }])
+                }
+                "/save" => {
+                    let [path] = &msg.args[..] else {
+                        unreachable!()
+                    };
+                    let path = path.clone().string().unwrap();
+                    Ok(vec![GraphOp::Save { path }])
+                }
+                "/load" => {
+                    let [path] = &msg.args[..] else {
+                        unreachable!()
+                    };
+                    let path = path.clone().string().unwrap();
+                    Ok(vec![GraphOp::Load { path }])
+                }
                 e => Err(InvalidGraphOpError(e.to_string()).into()),
             },
             OscPacket::Bundle(bund) => {
@@ -340,10 +688,14 @@ impl GraphOp {
                 addr: "/add_constant_string".to_string(),
                 args: vec![OscType::String(c)],
             }),
-            GraphOp::AddProcessor { name } => OscPacket::Message(OscMessage {
-                addr: "/add_processor".to_string(),
-                args: vec![OscType::String(name)],
-            }),
+            GraphOp::AddProcessor { name, args } => {
+                let mut osc_args = vec![OscType::String(name)];
+                osc_args.extend(args);
+                OscPacket::Message(OscMessage {
+                    addr: "/add_processor".to_string(),
+                    args: osc_args,
+                })
+            }
             GraphOp::Connect {
                 source,
                 source_output,
@@ -370,6 +722,30 @@ impl GraphOp {
                     args: vec![target, replacement],
                 })
             }
+            GraphOp::DumpGraph => OscPacket::Message(OscMessage {
+                addr: "/dump_graph".to_string(),
+                args: vec![],
+            }),
+            GraphOp::ListProcessors => OscPacket::Message(OscMessage {
+                addr: "/query/processors".to_string(),
+                args: vec![],
+            }),
+            GraphOp::ListNodes => OscPacket::Message(OscMessage {
+                addr: "/query/nodes".to_string(),
+                args: vec![],
+            }),
+            GraphOp::NodeInfo { node } => OscPacket::Message(OscMessage {
+                addr: "/query/node_info".to_string(),
+                args: vec![OscType::Int(node.index() as i32)],
+            }),
+            GraphOp::Save { path } => OscPacket::Message(OscMessage {
+                addr: "/save".to_string(),
+                args: vec![OscType::String(path)],
+            }),
+            GraphOp::Load { path } => OscPacket::Message(OscMessage {
+                addr: "/load".to_string(),
+                args: vec![OscType::String(path)],
+            }),
         }
     }
 }
@@ -377,18 +753,3 @@ impl GraphOp {
 #[derive(Error, Debug)]
 #[error("Unknown processor")]
 pub struct UnknownProcessorError;
-
-fn add_proc_by_name(graph: &Graph, name: &str) -> Result<NodeIndex> {
-    macro_rules! procs {
-        ($($proc:ident),* $(,)?) => {
-            match name {
-                $(
-                    stringify!($proc) => graph.node($proc::default()),
-                )*
-                _ => return Err(UnknownProcessorError.into()),
-            }
-        };
-    }
-    let node = procs!(Add, Sub, Mul, Div, Neg, SineOscillator, BlSawOscillator);
-    Ok(node.id())
-}
@@ -1,9 +1,26 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, sync::Arc};
 
 use anyhow::Result;
-use clap::Parser;
+use async_trait::async_trait;
+use clap::{Parser, ValueEnum};
+use futures_util::{SinkExt, StreamExt};
 use raug::prelude::{AudioBackend, AudioDevice};
-use tokio::net::UdpSocket;
+use rosc::{OscMessage, OscPacket, OscType};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, Mutex};
+use tokio_tungstenite::tungstenite::Message;
+
+use raug_server::graph::GraphOp;
+use raug_server::server::{SeqAction, Server};
+
+/// Control transport the server listens on.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum TransportKind {
+    /// Fire-and-forget / acknowledged OSC over UDP.
+    Udp,
+    /// OSC as binary frames over WebSocket, with live broadcast of responses.
+    Ws,
+}
 
 #[derive(Parser)]
 struct Args {
@@ -17,53 +34,230 @@ struct Args {
     backend: AudioBackend,
     #[clap(short, long, value_parser = AudioDevice::from_str, default_value = "default")]
     device: AudioDevice,
+    #[clap(short, long, value_enum, default_value = "udp")]
+    transport: TransportKind,
 }
 
-async fn server(args: Args) -> Result<()> {
-    let Args {
-        addr,
-        inputs,
-        outputs,
-        backend,
-        device,
-    } = args;
-    let sock = UdpSocket::bind(addr).await?;
+/// A control transport that feeds decoded [`OscPacket`]s into
+/// [`Server::apply_osc`] and returns the resulting responses to clients.
+#[async_trait]
+trait Transport {
+    async fn serve(self: Box<Self>, server: Server) -> Result<()>;
+}
+
+/// Notification broadcast to WebSocket clients whenever the graph is mutated.
+fn graph_changed() -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: "/notify/graph_changed".to_string(),
+        args: vec![OscType::Int(0)],
+    })
+}
 
-    let mut server = raug_server::server::Server::new(inputs, outputs, backend, device);
+/// The original acknowledged-OSC-over-UDP transport.
+struct UdpTransport {
+    addr: SocketAddr,
+}
 
-    let mut buf = [0u8; rosc::decoder::MTU];
+#[async_trait]
+impl Transport for UdpTransport {
+    async fn serve(self: Box<Self>, mut server: Server) -> Result<()> {
+        let sock = UdpSocket::bind(self.addr).await?;
+        let mut buf = [0u8; rosc::decoder::MTU];
 
-    'recv: loop {
-        match sock.recv_from(&mut buf).await {
-            Ok((size, client_addr)) => {
-                let packet = match rosc::decoder::decode_udp(&buf[..size]) {
-                    Ok((_, packet)) => packet,
-                    Err(e) => {
-                        log::error!("Malformed packet: {e}");
-                        continue 'recv;
+        'recv: loop {
+            match sock.recv_from(&mut buf).await {
+                Ok((size, client_addr)) => {
+                    let packet = match rosc::decoder::decode_udp(&buf[..size]) {
+                        Ok((_, packet)) => packet,
+                        Err(e) => {
+                            log::error!("Malformed packet: {e}");
+                            continue 'recv;
+                        }
+                    };
+
+                    log::debug!("[{}] {:?}", client_addr, &packet);
+
+                    let (seq, packet) = raug_server::graph::take_seq(packet);
+
+                    // A retransmit of an already-applied op is answered from the
+                    // per-client cache instead of being applied twice.
+                    if let Some(seq) = seq {
+                        match server.classify_seq(client_addr, seq) {
+                            SeqAction::Apply => {}
+                            SeqAction::Resend(cached) => {
+                                log::debug!("resending cached response for seq {seq}");
+                                for resp in cached {
+                                    let buf = rosc::encoder::encode(&resp.to_osc())?;
+                                    sock.send_to(&buf, client_addr).await?;
+                                }
+                                let ack = rosc::encoder::encode(&raug_server::graph::ack(seq))?;
+                                sock.send_to(&ack, client_addr).await?;
+                                continue 'recv;
+                            }
+                            SeqAction::AckOnly => {
+                                log::debug!("re-acking old duplicate seq {seq}");
+                                let ack = rosc::encoder::encode(&raug_server::graph::ack(seq))?;
+                                sock.send_to(&ack, client_addr).await?;
+                                continue 'recv;
+                            }
+                        }
                     }
-                };
 
-                log::debug!("[{}] {:?}", client_addr, &packet);
+                    let resps = match server.apply_osc(&packet) {
+                        Ok(resps) => resps,
+                        Err(e) => {
+                            log::error!("failed to apply op from {client_addr}: {e}");
+                            continue 'recv;
+                        }
+                    };
 
-                let resps = server.apply_osc(&packet)?;
-                for resp in resps {
-                    let buf = rosc::encoder::encode(&resp.to_osc())?;
-                    sock.send_to(&buf, client_addr).await?;
+                    for resp in &resps {
+                        let buf = rosc::encoder::encode(&resp.clone().to_osc())?;
+                        sock.send_to(&buf, client_addr).await?;
+                    }
+
+                    // Acknowledge only after applying, and cache the response so a
+                    // lost response followed by a retransmit is recovered rather
+                    // than reported as a failure to the client.
+                    if let Some(seq) = seq {
+                        let ack = rosc::encoder::encode(&raug_server::graph::ack(seq))?;
+                        sock.send_to(&ack, client_addr).await?;
+                        server.record_seq(client_addr, seq, resps);
+                    }
+                }
+                Err(e) => {
+                    log::error!("recv_from failed: {}", e);
+                    return Err(e.into());
                 }
             }
-            Err(e) => {
-                log::error!("recv_from failed: {}", e);
-                return Err(e.into());
+        }
+    }
+}
+
+/// An HTTP/WebSocket transport. OSC packets arrive as binary frames, and every
+/// response plus a graph-change notification is broadcast to all connected
+/// clients so a web UI stays in sync with mutations made by others.
+struct WsTransport {
+    addr: SocketAddr,
+}
+
+#[async_trait]
+impl Transport for WsTransport {
+    async fn serve(self: Box<Self>, server: Server) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let server = Arc::new(Mutex::new(server));
+        let (tx, _rx) = broadcast::channel::<Vec<u8>>(256);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = server.clone();
+            let tx = tx.clone();
+            let rx = tx.subscribe();
+            tokio::spawn(async move {
+                if let Err(e) = handle_ws(stream, server, tx, rx).await {
+                    log::error!("ws connection {peer} error: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_ws(
+    stream: TcpStream,
+    server: Arc<Mutex<Server>>,
+    tx: broadcast::Sender<Vec<u8>>,
+    mut rx: broadcast::Receiver<Vec<u8>>,
+) -> Result<()> {
+    let ws = tokio_tungstenite::accept_async(stream).await?;
+    let (mut write, mut read) = ws.split();
+
+    loop {
+        tokio::select! {
+            incoming = read.next() => {
+                let Some(incoming) = incoming else { break };
+                let incoming = match incoming {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        log::error!("ws read failed: {e}");
+                        continue;
+                    }
+                };
+                if let Message::Binary(data) = incoming {
+                    let packet = match rosc::decoder::decode_udp(&data) {
+                        Ok((_, packet)) => packet,
+                        Err(e) => {
+                            log::error!("malformed ws frame: {e}");
+                            continue;
+                        }
+                    };
+                    let mutated = GraphOp::try_from_osc(&packet)
+                        .map(|ops| {
+                            ops.iter()
+                                .any(|op| op.is_recordable() || matches!(op, GraphOp::Load { .. }))
+                        })
+                        .unwrap_or(false);
+                    let resps = {
+                        let mut server = server.lock().await;
+                        match server.apply_osc(&packet) {
+                            Ok(resps) => resps,
+                            Err(e) => {
+                                log::error!("failed to apply ws op: {e}");
+                                continue;
+                            }
+                        }
+                    };
+                    for resp in resps {
+                        if let Ok(buf) = rosc::encoder::encode(&resp.to_osc()) {
+                            let _ = tx.send(buf);
+                        }
+                    }
+                    // Only notify on actual graph mutations, not read-only queries.
+                    if mutated {
+                        if let Ok(buf) = rosc::encoder::encode(&graph_changed()) {
+                            let _ = tx.send(buf);
+                        }
+                    }
+                }
+            }
+            broadcasted = rx.recv() => {
+                match broadcasted {
+                    Ok(bytes) => write.send(Message::Binary(bytes)).await?,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        log::warn!("ws client lagged, dropped {n} messages");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
             }
         }
     }
+
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<()> {
+    let Args {
+        addr,
+        inputs,
+        outputs,
+        backend,
+        device,
+        transport,
+    } = args;
+
+    let server = Server::new(inputs, outputs, backend, device);
+
+    let transport: Box<dyn Transport> = match transport {
+        TransportKind::Udp => Box::new(UdpTransport { addr }),
+        TransportKind::Ws => Box::new(WsTransport { addr }),
+    };
+
+    transport.serve(server).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
-    server(args).await?;
+    run(args).await?;
     Ok(())
 }
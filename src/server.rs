@@ -1,20 +1,144 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::net::SocketAddr;
+
 use anyhow::Result;
 use raug::prelude::*;
-use rosc::OscPacket;
+use raug_ext::prelude::{BlSawOscillator, SineOscillator};
+use raug_graph::graph::NodeIndex;
+use rosc::{OscPacket, OscType};
+use serde::{Deserialize, Serialize};
+
+use crate::graph::{GraphOp, GraphOpResponse, LogEntry, NameOrIndex, UnknownProcessorError};
+
+/// On-disk form of a [`LogEntry`]: the op encoded as an OSC packet plus the index
+/// of the node it created, if any. Uses only types that implement serde natively.
+#[derive(Serialize, Deserialize)]
+struct SavedEntry {
+    op: Vec<u8>,
+    created: Option<u32>,
+}
+
+/// A factory that builds a processor node from OSC construction arguments.
+pub type ProcessorFactory =
+    Box<dyn Fn(&Graph, &[OscType]) -> Result<NodeIndex> + Send + Sync>;
+
+/// A runtime, extensible mapping from processor name to a construction factory.
+///
+/// Embedders register additional processor types with [`Server::register_processor`]
+/// without having to edit this crate.
+#[derive(Default)]
+pub struct ProcessorRegistry {
+    factories: HashMap<String, ProcessorFactory>,
+}
+
+impl ProcessorRegistry {
+    /// Registers `factory` under `name`, replacing any previous entry.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        factory: impl Fn(&Graph, &[OscType]) -> Result<NodeIndex> + Send + Sync + 'static,
+    ) {
+        self.factories.insert(name.into(), Box::new(factory));
+    }
+
+    /// Returns the factory registered under `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&ProcessorFactory> {
+        self.factories.get(name)
+    }
+
+    /// Iterates over the registered processor names.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(|s| s.as_str())
+    }
+
+    /// Builds the registry preloaded with the processors this server ships.
+    fn with_defaults() -> Self {
+        let mut reg = Self::default();
+        reg.register("Add", |graph, _| Ok(graph.node(Add::default()).id()));
+        reg.register("Sub", |graph, _| Ok(graph.node(Sub::default()).id()));
+        reg.register("Mul", |graph, _| Ok(graph.node(Mul::default()).id()));
+        reg.register("Div", |graph, _| Ok(graph.node(Div::default()).id()));
+        reg.register("Neg", |graph, _| Ok(graph.node(Neg::default()).id()));
+        reg.register("SineOscillator", |graph, args| {
+            Ok(graph
+                .node(SineOscillator::new(arg_f32(args, 0).unwrap_or(440.0)))
+                .id())
+        });
+        reg.register("BlSawOscillator", |graph, args| {
+            Ok(graph
+                .node(BlSawOscillator::new(arg_f32(args, 0).unwrap_or(440.0)))
+                .id())
+        });
+        reg
+    }
+}
 
-use crate::graph::{GraphOp, GraphOpResponse};
+/// Reads a float-valued construction argument, coercing ints to floats.
+fn arg_f32(args: &[OscType], index: usize) -> Option<f32> {
+    match args.get(index) {
+        Some(OscType::Float(f)) => Some(*f),
+        Some(OscType::Int(i)) => Some(*i as f32),
+        _ => None,
+    }
+}
 
 pub struct Server {
     graph: Graph,
     running_graph: Option<RunningGraph>,
     mixer: Vec<Node>,
     master: Node,
+    inputs: usize,
+    outputs: usize,
     backend: AudioBackend,
     device: AudioDevice,
+    clients: HashMap<SocketAddr, ClientState>,
+    registry: ProcessorRegistry,
+    log: Vec<LogEntry>,
+}
+
+/// Per-client reliable-transport state: the cached responses of recently applied
+/// ops keyed by sequence id, and the highest contiguous sequence seen so far.
+/// Entries below the contiguous watermark are pruned, keeping the cache bounded
+/// by the out-of-order window rather than the session length.
+#[derive(Default)]
+struct ClientState {
+    applied: HashMap<u32, Vec<GraphOpResponse>>,
+    contiguous: u32,
+}
+
+/// What the reliable transport should do with an incoming sequence id.
+pub enum SeqAction {
+    /// The op is new and should be applied.
+    Apply,
+    /// The op was already applied; resend these cached responses.
+    Resend(Vec<GraphOpResponse>),
+    /// The op is an old duplicate below the watermark; just re-ack it.
+    AckOnly,
 }
 
 impl Server {
     pub fn new(inputs: usize, outputs: usize, backend: AudioBackend, device: AudioDevice) -> Self {
+        let (graph, mixer, master) = Self::build_graph(inputs, outputs);
+
+        Self {
+            graph,
+            running_graph: None,
+            mixer,
+            master,
+            inputs,
+            outputs,
+            backend,
+            device,
+            clients: HashMap::new(),
+            registry: ProcessorRegistry::with_defaults(),
+            log: Vec::new(),
+        }
+    }
+
+    /// Builds a fresh graph with the mixer channels summed into a master node
+    /// driving the DAC. Shared by construction and by [`Server::load`].
+    fn build_graph(inputs: usize, outputs: usize) -> (Graph, Vec<Node>, Node) {
         let graph = Graph::new(inputs, outputs);
 
         let mixer = vec![
@@ -25,14 +149,62 @@ impl Server {
 
         graph.dac((&master, &master));
 
-        Self {
-            graph,
-            running_graph: None,
-            mixer,
-            master,
-            backend,
-            device,
+        (graph, mixer, master)
+    }
+
+    /// Registers a processor factory so clients can construct `name` via
+    /// `/add_processor`. Factories receive the graph and the trailing OSC
+    /// construction arguments.
+    pub fn register_processor(
+        &mut self,
+        name: impl Into<String>,
+        factory: impl Fn(&Graph, &[OscType]) -> Result<NodeIndex> + Send + Sync + 'static,
+    ) {
+        self.registry.register(name, factory);
+    }
+
+    /// Constructs a node for the named processor, passing `args` to its factory.
+    pub fn add_processor(&self, name: &str, args: &[OscType]) -> Result<NodeIndex> {
+        let factory = self.registry.get(name).ok_or(UnknownProcessorError)?;
+        factory(&self.graph, args)
+    }
+
+    /// Returns the names of every registered processor, sorted for stable output.
+    pub fn processor_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.registry.names().map(|s| s.to_string()).collect();
+        names.sort();
+        names
+    }
+
+    /// Classifies a reliably-sent sequence id for `client`, telling the transport
+    /// whether to apply the op, resend a cached response, or merely re-ack an old
+    /// duplicate. Sequence state is tracked per client address, since each client
+    /// numbers its ops independently from 1.
+    pub fn classify_seq(&self, client: SocketAddr, seq: u32) -> SeqAction {
+        match self.clients.get(&client) {
+            Some(state) => {
+                if let Some(responses) = state.applied.get(&seq) {
+                    SeqAction::Resend(responses.clone())
+                } else if seq <= state.contiguous {
+                    SeqAction::AckOnly
+                } else {
+                    SeqAction::Apply
+                }
+            }
+            None => SeqAction::Apply,
+        }
+    }
+
+    /// Records the responses produced by applying `seq` for `client`, advancing
+    /// the highest contiguous sequence seen and pruning cached responses that have
+    /// fallen below the watermark (and so can no longer be retransmitted).
+    pub fn record_seq(&mut self, client: SocketAddr, seq: u32, responses: Vec<GraphOpResponse>) {
+        let state = self.clients.entry(client).or_default();
+        state.applied.insert(seq, responses);
+        while state.applied.contains_key(&(state.contiguous + 1)) {
+            state.contiguous += 1;
         }
+        state.applied.retain(|&s, _| s >= state.contiguous);
     }
 
     pub fn graph(&self) -> &Graph {
@@ -43,6 +215,73 @@ impl Server {
         self.mixer.len()
     }
 
+    /// Serializes the current graph topology to Graphviz DOT.
+    ///
+    /// Every node becomes a `digraph` node labeled by its processor type name,
+    /// and every connection becomes an edge labeled with the source output and
+    /// target input ports, preferring the port names and falling back to the
+    /// index for a port without a name (e.g. `0:freq` for an unnamed output 0
+    /// feeding a `freq` input). The mixer/master chain that feeds the DAC is grouped into a
+    /// highlighted cluster so the signal path is easy to follow.
+    pub fn dump_dot(&self) -> String {
+        let mut signal_path: HashSet<usize> = self.mixer.iter().map(|n| n.id().index()).collect();
+        signal_path.insert(self.master.id().index());
+
+        let mut out = String::from("digraph patch {\n");
+        self.graph.with_inner(|graph| {
+            let label_of = |node| {
+                graph
+                    .node_weight(node)
+                    .map(|n| n.name().to_string())
+                    .unwrap_or_else(|| "<unknown>".to_string())
+            };
+
+            // Group the signal path to the DAC into a distinct cluster.
+            out.push_str("    subgraph cluster_master {\n");
+            out.push_str("        label=\"master\";\n");
+            out.push_str("        style=filled;\n        color=lightblue;\n");
+            for node in graph.node_indices() {
+                if signal_path.contains(&node.index()) {
+                    let _ = writeln!(out, "        n{} [label=\"{}\"];", node.index(), label_of(node));
+                }
+            }
+            out.push_str("    }\n");
+
+            for node in graph.node_indices() {
+                if !signal_path.contains(&node.index()) {
+                    let _ = writeln!(out, "    n{} [label=\"{}\"];", node.index(), label_of(node));
+                }
+            }
+
+            for edge in graph.edge_indices() {
+                let Some((source, target)) = graph.edge_endpoints(edge) else {
+                    continue;
+                };
+                let Some(weight) = graph.edge_weight(edge) else {
+                    continue;
+                };
+                let out_port = graph
+                    .node_weight(source)
+                    .and_then(|n| n.output_names().get(weight.source_output as usize).cloned())
+                    .unwrap_or_else(|| weight.source_output.to_string());
+                let in_port = graph
+                    .node_weight(target)
+                    .and_then(|n| n.input_names().get(weight.target_input as usize).cloned())
+                    .unwrap_or_else(|| weight.target_input.to_string());
+                let _ = writeln!(
+                    out,
+                    "    n{} -> n{} [label=\"{}:{}\"];",
+                    source.index(),
+                    target.index(),
+                    out_port,
+                    in_port,
+                );
+            }
+        });
+        out.push_str("}\n");
+        out
+    }
+
     pub fn mixer_channel(&mut self, index: usize) -> &Input {
         if index < self.num_mixer_channels() {
             self.mixer[index].input(0)
@@ -58,6 +297,47 @@ impl Server {
         }
     }
 
+    /// Lists every live node index paired with its processor type name.
+    pub fn list_nodes(&self) -> Vec<(NodeIndex, String)> {
+        self.graph.with_inner(|graph| {
+            graph
+                .node_indices()
+                .map(|node| {
+                    let name = graph
+                        .node_weight(node)
+                        .map(|w| w.name().to_string())
+                        .unwrap_or_default();
+                    (node, name)
+                })
+                .collect()
+        })
+    }
+
+    /// Resolves an output port of `node` to its index, looking up the name in the
+    /// node's output ports when given [`NameOrIndex::Name`].
+    pub fn resolve_output(&self, node: NodeIndex, port: &NameOrIndex) -> Option<u32> {
+        match port {
+            NameOrIndex::Index(index) => Some(*index),
+            NameOrIndex::Name(name) => self.graph.with_inner(|graph| {
+                graph.node_weight(node).and_then(|w| {
+                    w.output_names()
+                        .iter()
+                        .position(|n| n == name)
+                        .map(|i| i as u32)
+                })
+            }),
+        }
+    }
+
+    /// Returns the named inputs and outputs of `node`, or `None` if it is absent.
+    pub fn node_info(&self, node: NodeIndex) -> Option<(Vec<String>, Vec<String>)> {
+        self.graph.with_inner(|graph| {
+            graph
+                .node_weight(node)
+                .map(|w| (w.input_names(), w.output_names()))
+        })
+    }
+
     pub fn start_graph(&mut self) -> Result<()> {
         let graph = self
             .graph
@@ -79,9 +359,76 @@ impl Server {
         let ops = GraphOp::try_from_osc(packet)?;
 
         for op in ops {
-            responses.push(op.apply(self)?);
+            let response = op.apply(self)?;
+            if op.is_recordable() {
+                self.log.push(LogEntry {
+                    op,
+                    created: response.as_node_index().copied(),
+                });
+            }
+            responses.push(response);
         }
 
         Ok(responses)
     }
+
+    /// Serializes the session op log to `path` as JSON, producing a shareable,
+    /// replayable patch file. Each op is stored in its OSC wire form so the
+    /// format depends only on the existing encoding rather than on serde support
+    /// in `rosc`/`petgraph`.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let entries: Vec<SavedEntry> = self
+            .log
+            .iter()
+            .map(|entry| {
+                Ok(SavedEntry {
+                    op: rosc::encoder::encode(&entry.op.clone().to_osc())?,
+                    created: entry.created.map(|n| n.index() as u32),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let json = serde_json::to_string_pretty(&entries)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Clears the current graph and rebuilds it by replaying the op log stored at
+    /// `path`. Because nodes are created in log order, replay reproduces the same
+    /// index assignment; stored node references are remapped onto the indices
+    /// produced during replay for robustness.
+    pub fn load(&mut self, path: &str) -> Result<()> {
+        let json = std::fs::read_to_string(path)?;
+        let saved: Vec<SavedEntry> = serde_json::from_str(&json)?;
+        let entries: Vec<LogEntry> = saved
+            .into_iter()
+            .map(|entry| {
+                let (_, packet) = rosc::decoder::decode_udp(&entry.op)?;
+                let op = GraphOp::try_from_osc(&packet)?.remove(0);
+                Ok(LogEntry {
+                    op,
+                    created: entry.created.map(|i| NodeIndex::new(i as usize)),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        self.stop_graph()?;
+        let (graph, mixer, master) = Self::build_graph(self.inputs, self.outputs);
+        self.graph = graph;
+        self.mixer = mixer;
+        self.master = master;
+        self.log = Vec::new();
+
+        let mut remap: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        for entry in entries {
+            let op = entry.op.remapped(&remap);
+            let response = op.apply(self)?;
+            let created = response.as_node_index().copied();
+            if let (Some(old), Some(new)) = (entry.created, created) {
+                remap.insert(old, new);
+            }
+            self.log.push(LogEntry { op, created });
+        }
+
+        Ok(())
+    }
 }